@@ -0,0 +1,184 @@
+//! The `ledger` module provides a persistent, append-only log of `Entry`s on
+//! disk, and helpers for reading the votes carried by a block of entries. The
+//! write stage grows the ledger as the leader produces entries.
+
+use bincode::serialize;
+use entry::Entry;
+use hash::Hash;
+use result::Result;
+use signature::PublicKey;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use transaction::Vote;
+
+/// Segment files live in the ledger directory named `ledger-<index>`.
+const SEGMENT_PREFIX: &str = "ledger-";
+
+/// A block of entries (e.g. a `Vec<Entry>`) that can report the votes carried
+/// by its transactions.
+pub trait Block {
+    /// Collect the `(validator, vote, last_id)` tuples carried by the block.
+    fn votes(&self) -> Vec<(PublicKey, Vote, Hash)>;
+}
+
+impl Block for [Entry] {
+    fn votes(&self) -> Vec<(PublicKey, Vote, Hash)> {
+        self.iter()
+            .flat_map(|entry| entry.transactions.iter().filter_map(|tx| tx.vote()))
+            .collect()
+    }
+}
+
+/// Append-only writer over the ledger directory. Entries are written to the
+/// current segment as a little-endian length prefix followed by the
+/// bincode-serialized `Entry`.
+pub struct LedgerWriter {
+    path: PathBuf,
+    segment: u64,
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+impl LedgerWriter {
+    /// Open `path` as a ledger directory, resuming the highest-numbered live
+    /// segment so a restarted writer appends where it left off.
+    pub fn recover<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+        let segment = Self::highest_segment(&path)?.unwrap_or(0);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::segment_path(&path, segment))?;
+        let written = file.metadata()?.len();
+        Ok(LedgerWriter {
+            path,
+            segment,
+            writer: BufWriter::new(file),
+            written,
+        })
+    }
+
+    fn segment_path(path: &Path, segment: u64) -> PathBuf {
+        path.join(format!("{}{}", SEGMENT_PREFIX, segment))
+    }
+
+    /// Highest live segment index present in `path`, if any.
+    fn highest_segment(path: &Path) -> Result<Option<u64>> {
+        let mut highest = None;
+        for entry in fs::read_dir(path)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(SEGMENT_PREFIX) {
+                if let Ok(n) = name[SEGMENT_PREFIX.len()..].parse::<u64>() {
+                    highest = Some(highest.map_or(n, |h: u64| h.max(n)));
+                }
+            }
+        }
+        Ok(highest)
+    }
+
+    /// Append `entries` to the current segment.
+    pub fn write_entries(&mut self, entries: Vec<Entry>) -> Result<()> {
+        for entry in entries {
+            let bytes = serialize(&entry)?;
+            let len = bytes.len() as u64;
+            self.writer.write_all(&len.to_le_bytes())?;
+            self.writer.write_all(&bytes)?;
+            self.written += 8 + len;
+        }
+        Ok(())
+    }
+
+    /// Flush buffered bytes to the OS. This does not guarantee the data has
+    /// reached stable storage.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Flush buffered bytes and force the segment's data to stable storage with
+    /// `fsync` (`File::sync_data`), so a caller can rely on the entry being
+    /// durable once this returns.
+    pub fn fsync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Number of bytes written to the current segment so far.
+    pub fn segment_len(&self) -> Result<u64> {
+        Ok(self.written)
+    }
+
+    /// Seal the current segment, start a fresh one, and return the path of the
+    /// sealed segment so the caller can archive or compress it off the write
+    /// path. The writer resumes appending to the new segment.
+    pub fn rotate_segment(&mut self) -> Result<Option<PathBuf>> {
+        self.fsync()?;
+        let sealed = Self::segment_path(&self.path, self.segment);
+        self.segment += 1;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::segment_path(&self.path, self.segment))?;
+        self.writer = BufWriter::new(file);
+        self.written = 0;
+        Ok(Some(sealed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Read;
+
+    fn tmp_ledger(name: &str) -> PathBuf {
+        let mut path = env::temp_dir();
+        path.push(format!("solana-ledger-test-{}", name));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn test_rotate_segment_seals_and_resumes() {
+        let path = tmp_ledger("rotate");
+        {
+            let mut writer = LedgerWriter::recover(&path).unwrap();
+            assert_eq!(writer.segment, 0);
+            let sealed = writer.rotate_segment().unwrap().unwrap();
+            assert_eq!(sealed, LedgerWriter::segment_path(&path, 0));
+            assert_eq!(writer.segment, 1);
+            assert_eq!(writer.segment_len().unwrap(), 0);
+        }
+        // recover resumes at the highest-numbered live segment.
+        let writer = LedgerWriter::recover(&path).unwrap();
+        assert_eq!(writer.segment, 1);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_fsync_flushes_to_disk() {
+        let path = tmp_ledger("fsync");
+        {
+            let mut writer = LedgerWriter::recover(&path).unwrap();
+            writer
+                .writer
+                .write_all(b"durable")
+                .and_then(|_| {
+                    writer.written += 7;
+                    Ok(())
+                }).unwrap();
+            writer.fsync().unwrap();
+        }
+        let mut contents = Vec::new();
+        File::open(LedgerWriter::segment_path(&path, 0))
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(&contents, b"durable");
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+//! The `vote_stage` module implements the leader's confirmation vote. The
+//! leader confirms the cluster's view of the ledger once a stake-weighted
+//! supermajority of validators has voted on a common last id, replacing the
+//! old simple-majority validator count.
+
+use bank::Bank;
+use bincode::serialize;
+use counter::Counter;
+use crdt::Crdt;
+use hash::Hash;
+use log::Level;
+use packet::{BlobRecycler, SharedBlob};
+use result::Result;
+use signature::{Keypair, PublicKey};
+use std::sync::atomic::AtomicUsize;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use timing::timestamp;
+use transaction::Transaction;
+
+/// Channel the leader pushes its signed vote blobs onto.
+pub type VoteBlobSender = Sender<Vec<SharedBlob>>;
+
+/// Minimum interval between the leader's confirmation votes, in ms.
+pub const VOTE_TIMEOUT_MS: u64 = 1000;
+
+/// Build a signed vote blob for `last_id` addressed to the gossip peers.
+fn create_leader_vote_blob(
+    keypair: &Keypair,
+    crdt: &Arc<RwLock<Crdt>>,
+    blob_recycler: &BlobRecycler,
+    last_id: Hash,
+) -> Result<SharedBlob> {
+    let (vote, addr) = crdt.write().unwrap().new_vote(last_id)?;
+    let tx = Transaction::new_vote(keypair, vote, last_id, 0);
+    let blob = blob_recycler.allocate();
+    {
+        let mut b = blob.write().unwrap();
+        let data = serialize(&tx)?;
+        let len = data.len();
+        b.data[..len].copy_from_slice(&data);
+        b.meta.size = len;
+        b.meta.set_addr(&addr);
+    }
+    Ok(blob)
+}
+
+/// Cast the leader's votes.
+///
+/// The periodic liveness path runs on every call, refreshing the newest valid
+/// validator timestamp regardless of stake, so the cluster's liveness view
+/// stays fresh even while the leader is still accumulating stake. The
+/// confirmation vote — the one that used to fire on a raw validator-count
+/// majority (the old `//TODO(anatoly)` logic) — now fires only once
+/// `confirmed_stake` crosses `vote_threshold`, i.e. a stake-weighted
+/// supermajority has voted on a common last id.
+pub fn send_leader_vote(
+    id: &PublicKey,
+    keypair: &Keypair,
+    bank: &Arc<Bank>,
+    crdt: &Arc<RwLock<Crdt>>,
+    blob_recycler: &BlobRecycler,
+    vote_blob_sender: &VoteBlobSender,
+    last_vote: &mut u64,
+    last_valid_validator_timestamp: &mut u64,
+    confirmed_stake: f64,
+    vote_threshold: f64,
+) -> Result<()> {
+    let now = timestamp();
+
+    // Liveness: track the newest valid validator timestamp on every call so a
+    // leader still accumulating stake does not let the cluster view go stale.
+    if let Some(ts) = crdt.read().unwrap().valid_last_ids_timestamp(id) {
+        *last_valid_validator_timestamp = ts;
+    }
+
+    // Confirmation: the stake-weighted supermajority replaces the old
+    // count-based majority. Rate-limited to VOTE_TIMEOUT_MS.
+    if confirmed_stake >= vote_threshold && now - *last_vote > VOTE_TIMEOUT_MS {
+        let last_id = bank.last_id();
+        let blob = create_leader_vote_blob(keypair, crdt, blob_recycler, last_id)?;
+        inc_new_counter_info!("vote_stage-confirmed_stake_vote", 1);
+        vote_blob_sender.send(vec![blob])?;
+        *last_vote = now;
+    }
+    Ok(())
+}
@@ -6,15 +6,25 @@ use bank::Bank;
 use counter::Counter;
 use crdt::Crdt;
 use entry::Entry;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hash::Hash;
 use ledger::{Block, LedgerWriter};
 use log::Level;
 use packet::BlobRecycler;
 use result::{Error, Result};
 use service::Service;
-use signature::Keypair;
+use signature::{Keypair, PublicKey};
+use transaction::Vote;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
 use std::net::UdpSocket;
+use std::path::PathBuf;
 use std::sync::atomic::AtomicUsize;
-use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::mpsc::{
+    channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError,
+};
 use std::sync::{Arc, RwLock};
 use std::thread::{self, Builder, JoinHandle};
 use std::time::{Duration, Instant};
@@ -22,18 +32,62 @@ use streamer::responder;
 use timing::{duration_as_ms, duration_as_s};
 use vote_stage::send_leader_vote;
 
+/// How hard the write stage forces entries to disk before broadcasting them.
+/// An entry is only handed to the broadcast channel once it is durable to the
+/// configured level. Only `FsyncPerBatch` keeps a leader from propagating
+/// entries it would lose on a local crash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DurabilityMode {
+    /// No flush after a batch; bytes may still sit in the writer's in-process
+    /// buffer when the batch is broadcast. Fastest, no guarantee on crash.
+    None,
+    /// `flush` the writer after each batch before broadcasting.
+    FlushPerBatch,
+    /// `flush` and `fsync` the writer after each batch before broadcasting.
+    FsyncPerBatch,
+}
+
+/// Controls how the write stage rotates ledger segments. When the live
+/// segment reaches `max_segment_bytes` it is sealed and a fresh segment is
+/// started; sealed segments are compressed off the write path so disk usage
+/// stays bounded and old history can be archived without stopping the leader.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RotationConfig {
+    pub max_segment_bytes: u64,
+}
+
 pub struct WriteStage {
     thread_hdls: Vec<JoinHandle<()>>,
 }
 
+/// Gzip a sealed ledger segment to `<segment>.gz` and remove the original.
+/// Runs on the background compression thread so the write path never blocks on
+/// compression.
+fn compress_segment(path: &PathBuf) -> io::Result<()> {
+    let gz_path = path.with_extension("gz");
+    let mut input = BufReader::new(File::open(path)?);
+    let output = BufWriter::new(File::create(&gz_path)?);
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)?;
+    Ok(())
+}
+
 impl WriteStage {
     /// Process any Entry items that have been published by the RecordStage.
     /// continuosly send entries out
     pub fn write_and_send_entries(
         crdt: &Arc<RwLock<Crdt>>,
         ledger_writer: &mut LedgerWriter,
-        entry_sender: &Sender<Vec<Entry>>,
+        entry_sender: &SyncSender<Vec<Entry>>,
         entry_receiver: &Receiver<Vec<Entry>>,
+        durability: DurabilityMode,
+        max_coalesce: usize,
+        rotation: Option<RotationConfig>,
+        sealed_sender: &Sender<PathBuf>,
+        bank: &Arc<Bank>,
+        stake_accum: &mut HashMap<Hash, HashMap<PublicKey, i64>>,
     ) -> Result<()> {
         let mut ventries = Vec::new();
         let entries = entry_receiver.recv_timeout(Duration::new(1, 0))?;
@@ -41,9 +95,18 @@ impl WriteStage {
         let mut num_txs = 0;
 
         ventries.push(entries);
-        while let Ok(more) = entry_receiver.try_recv() {
-            num_entries += more.len();
-            ventries.push(more);
+        // Bound how many entries we coalesce per iteration so a stalled
+        // downstream cannot make us buffer the whole receiver in `ventries`.
+        // A single oversized batch can still push us past `max_coalesce`, but
+        // we never pull more once the accumulated entry count is reached.
+        while num_entries < max_coalesce {
+            match entry_receiver.try_recv() {
+                Ok(more) => {
+                    num_entries += more.len();
+                    ventries.push(more);
+                }
+                Err(_) => break,
+            }
         }
 
         info!("write_stage entries: {}", num_entries);
@@ -64,16 +127,38 @@ impl WriteStage {
             crdt.write().unwrap().insert_votes(&votes);
             crdt_votes_total += duration_as_ms(&crdt_votes_start.elapsed());
 
+            // Fold the observed votes into the running stake accumulator,
+            // weighted by each voting validator's stake. Accumulating across
+            // iterations lets a supermajority assembled from votes spread over
+            // many batches be detected, which a per-batch snapshot would miss.
+            Self::accumulate_stake(stake_accum, votes, |id| bank.get_balance(id));
+
             ledger_writer.write_entries(entries.clone())?;
 
-            let register_entry_start = Instant::now();
-            register_entry_total += duration_as_ms(&register_entry_start.elapsed());
+            // Force the just-written batch to disk at the configured level
+            // before it is broadcast below; with `FsyncPerBatch` the cluster
+            // never sees an entry the leader could still lose on a crash.
+            let durability_start = Instant::now();
+            match durability {
+                DurabilityMode::None => (),
+                DurabilityMode::FlushPerBatch => ledger_writer.flush()?,
+                DurabilityMode::FsyncPerBatch => ledger_writer.fsync()?,
+            }
+            register_entry_total += duration_as_ms(&durability_start.elapsed());
 
             inc_new_counter_info!("write_stage-write_entries", entries.len());
 
-            //TODO(anatoly): real stake based voting needs to change this
-            //leader simply votes if the current set of validators have voted
-            //on a valid last id
+            // Seal and rotate the current segment once it reaches the
+            // configured threshold, handing the sealed segment off to the
+            // background compression thread.
+            if let Some(config) = rotation {
+                if ledger_writer.segment_len()? >= config.max_segment_bytes {
+                    if let Some(sealed) = ledger_writer.rotate_segment()? {
+                        inc_new_counter_info!("write_stage-segment_rotate", 1);
+                        sealed_sender.send(sealed)?;
+                    }
+                }
+            }
 
             trace!("New entries? {}", entries.len());
             let blob_send_start = Instant::now();
@@ -81,11 +166,30 @@ impl WriteStage {
                 inc_new_counter_info!("write_stage-recv_vote", votes.len());
                 inc_new_counter_info!("write_stage-broadcast_entries", entries.len());
                 trace!("broadcasting {}", entries.len());
-                entry_sender.send(entries)?;
+                // Push onto the bounded forwarding channel. If it is full the
+                // downstream is not keeping up, so block until there is room
+                // rather than growing memory without limit, and let operators
+                // see the leader is write-bound.
+                match entry_sender.try_send(entries) {
+                    Ok(()) => (),
+                    Err(TrySendError::Full(entries)) => {
+                        inc_new_counter_info!("write_stage-backpressure", 1);
+                        entry_sender.send(entries)?;
+                    }
+                    Err(TrySendError::Disconnected(entries)) => {
+                        // Surface as a SendError through the usual conversion.
+                        entry_sender.send(entries)?;
+                    }
+                }
             }
 
             blob_send_total += duration_as_ms(&blob_send_start.elapsed());
         }
+        // Only `FsyncPerBatch` actually issues an fsync; counting the cheaper
+        // `FlushPerBatch` here would mislabel flush latency as fsync latency.
+        if durability == DurabilityMode::FsyncPerBatch {
+            inc_new_counter_info!("write_stage-fsync_ms", register_entry_total as usize);
+        }
         info!("done write_stage txs: {} time {} ms txs/s: {} to_blobs_total: {} register_entry_total: {} blob_send_total: {} crdt_votes_total: {}",
               num_txs, duration_as_ms(&start.elapsed()),
               num_txs as f32 / duration_as_s(&start.elapsed()),
@@ -97,6 +201,58 @@ impl WriteStage {
         Ok(())
     }
 
+    /// Total staked tokens across the validators the cluster knows about, used
+    /// as the denominator for the stake-weighted vote. Each validator's stake
+    /// is its token balance in the `Bank`.
+    fn total_staked(crdt: &Arc<RwLock<Crdt>>, bank: &Arc<Bank>) -> i64 {
+        crdt.read()
+            .unwrap()
+            .table
+            .values()
+            .map(|node| bank.get_balance(&node.id))
+            .sum()
+    }
+
+    /// Fold `votes` into `accum`, keyed by last_id then by validator. Each
+    /// validator's stake is recorded once per last_id (votes cast on different
+    /// last_ids are kept separate), so votes for the same last_id arriving
+    /// across separate batches or iterations sum into a single total and a
+    /// validator seen more than once is never double-counted.
+    fn accumulate_stake<F>(
+        accum: &mut HashMap<Hash, HashMap<PublicKey, i64>>,
+        votes: &[(PublicKey, Vote, Hash)],
+        balance_of: F,
+    ) where
+        F: Fn(&PublicKey) -> i64,
+    {
+        for (validator_id, _, last_id) in votes {
+            accum
+                .entry(*last_id)
+                .or_insert_with(HashMap::new)
+                .entry(*validator_id)
+                .or_insert_with(|| balance_of(validator_id));
+        }
+    }
+
+    /// Largest fraction of `total_stake` backing any single last_id in the
+    /// accumulator, in `[0.0, 1.0]`. A supermajority must form behind one id,
+    /// so stake on different last_ids is never summed together, and the result
+    /// is clamped so double-reported stake cannot exceed 1.0.
+    fn confirmed_stake_fraction(
+        accum: &HashMap<Hash, HashMap<PublicKey, i64>>,
+        total_stake: i64,
+    ) -> f64 {
+        if total_stake <= 0 {
+            return 0.0;
+        }
+        let best: i64 = accum
+            .values()
+            .map(|voters| voters.values().sum())
+            .max()
+            .unwrap_or(0);
+        (best as f64 / total_stake as f64).min(1.0)
+    }
+
     /// Create a new WriteStage for writing and broadcasting entries.
     pub fn new(
         keypair: Keypair,
@@ -105,6 +261,11 @@ impl WriteStage {
         blob_recycler: BlobRecycler,
         ledger_path: &str,
         entry_receiver: Receiver<Vec<Entry>>,
+        durability: DurabilityMode,
+        channel_bound: usize,
+        max_coalesce: usize,
+        rotation: Option<RotationConfig>,
+        vote_threshold: f64,
     ) -> (Self, Receiver<Vec<Entry>>) {
         let (vote_blob_sender, vote_blob_receiver) = channel();
         let send = UdpSocket::bind("0.0.0.0:0").expect("bind");
@@ -114,7 +275,21 @@ impl WriteStage {
             blob_recycler.clone(),
             vote_blob_receiver,
         );
-        let (entry_sender, entry_receiver_forward) = channel();
+        let (entry_sender, entry_receiver_forward) = sync_channel(channel_bound);
+
+        // Background thread that compresses sealed ledger segments off the
+        // write path so rotation never blocks the leader.
+        let (sealed_sender, sealed_receiver): (Sender<PathBuf>, Receiver<PathBuf>) = channel();
+        let t_compress = Builder::new()
+            .name("solana-writer-compress".to_string())
+            .spawn(move || {
+                while let Ok(sealed) = sealed_receiver.recv() {
+                    if let Err(e) = compress_segment(&sealed) {
+                        inc_new_counter_info!("write_stage-segment_compress-error", 1);
+                        error!("failed to compress segment {:?}: {:?}", sealed, e);
+                    }
+                }
+            }).unwrap();
         let mut ledger_writer = LedgerWriter::recover(ledger_path).unwrap();
 
         let thread_hdl = Builder::new()
@@ -122,6 +297,7 @@ impl WriteStage {
             .spawn(move || {
                 let mut last_vote = 0;
                 let mut last_valid_validator_timestamp = 0;
+                let mut stake_accum: HashMap<Hash, HashMap<PublicKey, i64>> = HashMap::new();
                 let id = crdt.read().unwrap().id;
                 loop {
                     if let Err(e) = Self::write_and_send_entries(
@@ -129,6 +305,12 @@ impl WriteStage {
                         &mut ledger_writer,
                         &entry_sender,
                         &entry_receiver,
+                        durability,
+                        max_coalesce,
+                        rotation,
+                        &sealed_sender,
+                        &bank,
+                        &mut stake_accum,
                     ) {
                         match e {
                             Error::RecvTimeoutError(RecvTimeoutError::Disconnected) => break,
@@ -142,6 +324,20 @@ impl WriteStage {
                             }
                         }
                     };
+                    // Fraction of the total staked tokens behind the best
+                    // single last_id observed so far, accumulated across
+                    // iterations.
+                    let total_stake = Self::total_staked(&crdt, &bank);
+                    let confirmed_stake =
+                        Self::confirmed_stake_fraction(&stake_accum, total_stake);
+                    inc_new_counter_info!(
+                        "write_stage-confirmed_stake_pct",
+                        (confirmed_stake * 100.0) as usize
+                    );
+                    // Call every iteration so the periodic liveness path keeps
+                    // running; the stake-weighted supermajority decision itself
+                    // lives inside `send_leader_vote` (replacing the old
+                    // raw-count majority), gated on `vote_threshold`.
                     if let Err(e) = send_leader_vote(
                         &id,
                         &keypair,
@@ -151,14 +347,21 @@ impl WriteStage {
                         &vote_blob_sender,
                         &mut last_vote,
                         &mut last_valid_validator_timestamp,
+                        confirmed_stake,
+                        vote_threshold,
                     ) {
                         inc_new_counter_info!("write_stage-leader_vote-error", 1);
                         error!("{:?}", e);
                     }
+                    // Start a fresh confirmation round once a supermajority has
+                    // been reached for some last_id.
+                    if confirmed_stake >= vote_threshold {
+                        stake_accum.clear();
+                    }
                 }
             }).unwrap();
 
-        let thread_hdls = vec![t_responder, thread_hdl];
+        let thread_hdls = vec![t_responder, t_compress, thread_hdl];
         (WriteStage { thread_hdls }, entry_receiver_forward)
     }
 }
@@ -173,3 +376,68 @@ impl Service for WriteStage {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hash::hash;
+    use signature::{Keypair, KeypairUtil};
+
+    fn vote() -> Vote {
+        Vote {
+            version: 0,
+            contact_info_version: 0,
+        }
+    }
+
+    #[test]
+    fn test_accumulate_stake_dedupes_voter() {
+        let validator = Keypair::new().pubkey();
+        let last_id = hash(b"last_id");
+        let mut accum = HashMap::new();
+        // The same validator voting twice on the same last_id is counted once.
+        let votes = vec![(validator, vote(), last_id), (validator, vote(), last_id)];
+        WriteStage::accumulate_stake(&mut accum, &votes, |_| 60);
+        assert_eq!(WriteStage::confirmed_stake_fraction(&accum, 100), 0.6);
+    }
+
+    #[test]
+    fn test_accumulate_stake_sums_across_iterations() {
+        let a = Keypair::new().pubkey();
+        let b = Keypair::new().pubkey();
+        let last_id = hash(b"last_id");
+        let mut accum = HashMap::new();
+        let balances = move |id: &PublicKey| if *id == a { 40 } else { 40 };
+        // Two validators voting on the same last_id in separate batches sum.
+        WriteStage::accumulate_stake(&mut accum, &[(a, vote(), last_id)], balances);
+        assert!(WriteStage::confirmed_stake_fraction(&accum, 90) < 2.0 / 3.0);
+        WriteStage::accumulate_stake(&mut accum, &[(b, vote(), last_id)], balances);
+        // 80 of 90 staked tokens now clears the 2/3 threshold.
+        assert!(WriteStage::confirmed_stake_fraction(&accum, 90) >= 2.0 / 3.0);
+    }
+
+    #[test]
+    fn test_confirmed_stake_fraction_groups_by_last_id() {
+        let a = Keypair::new().pubkey();
+        let b = Keypair::new().pubkey();
+        let id_a = hash(b"a");
+        let id_b = hash(b"b");
+        let mut accum = HashMap::new();
+        // Votes on different last_ids are not summed: the best single id wins.
+        let balances = move |id: &PublicKey| if *id == a { 40 } else { 30 };
+        WriteStage::accumulate_stake(&mut accum, &[(a, vote(), id_a), (b, vote(), id_b)], balances);
+        assert_eq!(WriteStage::confirmed_stake_fraction(&accum, 100), 0.4);
+    }
+
+    #[test]
+    fn test_confirmed_stake_fraction_capped_and_guarded() {
+        let a = Keypair::new().pubkey();
+        let last_id = hash(b"last_id");
+        let mut accum = HashMap::new();
+        WriteStage::accumulate_stake(&mut accum, &[(a, vote(), last_id)], |_| 50);
+        // Stake exceeding the total is clamped to 1.0.
+        assert_eq!(WriteStage::confirmed_stake_fraction(&accum, 10), 1.0);
+        // A zero total stake yields 0.0 rather than dividing by zero.
+        assert_eq!(WriteStage::confirmed_stake_fraction(&accum, 0), 0.0);
+    }
+}